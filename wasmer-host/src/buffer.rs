@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasmer::{Instance, TypedFunc};
+
+/// A `(ptr, len)` pair packed into a single `u64` for crossing the host/guest
+/// ABI boundary as one value instead of two.
+///
+/// The pointer occupies the high 32 bits and the length the low 32 bits, so
+/// every export that moves a buffer can be typed as `TypedFunc<u64, u64>`
+/// rather than juggling ad-hoc pairs of `Val::I32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WasiBuffer {
+    pub ptr: u32,
+    pub len: u32,
+}
+
+/// Sentinel packed value a guest export returns instead of a real
+/// `WasiBuffer` to mean "I suspended on I/O or a spawned thread; poll the
+/// reactor and come back for the real result."
+pub const PENDING_SENTINEL: u64 = u64::MAX;
+
+/// What a raw export's packed `u64` return means: either the call finished
+/// and the value is a real `WasiBuffer`, or the guest suspended and the
+/// reactor needs to be driven before a result exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RawCallOutcome {
+    Ready(u64),
+    Pending,
+}
+
+fn classify_raw_result(packed: u64) -> RawCallOutcome {
+    if packed == PENDING_SENTINEL {
+        RawCallOutcome::Pending
+    } else {
+        RawCallOutcome::Ready(packed)
+    }
+}
+
+/// Repeatedly classifies `packed`, replacing it with `next()` each time it
+/// comes back `Pending`, until a real result is ready.
+///
+/// A single wakeup from the reactor only means the guest's async runtime
+/// made *some* progress, not that the whole call finished — a guest with
+/// two sequential `await`s, or a spawned thread whose own result is itself
+/// pending, can report `Pending` again right after being polled. `next` is
+/// expected to both drive the reactor and fetch the latest result, so this
+/// loops until that actually yields a non-sentinel value instead of trusting
+/// the first wakeup.
+fn resolve_pending(mut packed: u64, mut next: impl FnMut() -> Result<u64>) -> Result<u64> {
+    while let RawCallOutcome::Pending = classify_raw_result(packed) {
+        packed = next()?;
+    }
+    Ok(packed)
+}
+
+impl WasiBuffer {
+    pub fn new(ptr: u32, len: u32) -> Self {
+        WasiBuffer { ptr, len }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        ((self.ptr as u64) << 32) | (self.len as u64)
+    }
+
+    pub fn from_u64(packed: u64) -> Self {
+        WasiBuffer {
+            ptr: (packed >> 32) as u32,
+            len: packed as u32,
+        }
+    }
+}
+
+/// RAII guard over a buffer the guest allocated for us via `__alloc_buffer`.
+///
+/// Holding one keeps the region reserved; dropping it calls
+/// `__free_buffer`, so a call that bails out early with `?` still releases
+/// its guest allocation instead of leaking it.
+pub struct GuestBuffer {
+    buf: WasiBuffer,
+    free_fn: TypedFunc<u64, ()>,
+}
+
+impl GuestBuffer {
+    pub fn new(buf: WasiBuffer, free_fn: TypedFunc<u64, ()>) -> Self {
+        GuestBuffer { buf, free_fn }
+    }
+
+    pub fn buf(&self) -> WasiBuffer {
+        self.buf
+    }
+}
+
+impl Drop for GuestBuffer {
+    fn drop(&mut self) {
+        if let Err(err) = self.free_fn.call(self.buf.to_u64()) {
+            eprintln!("failed to free guest buffer at {:#x}: {}", self.buf.ptr, err);
+        }
+    }
+}
+
+/// A type-safe wrapper over a raw wasmer export that takes a packed
+/// `WasiBuffer` and returns one.
+///
+/// Calling it bincode-serializes `A`, has the guest allocate a right-sized
+/// buffer for it, invokes the underlying `TypedFunc<u64, u64>`, then reads
+/// the returned packed pointer/length back out and deserializes `R`.
+pub struct WasiFn<A, R> {
+    raw: TypedFunc<u64, u64>,
+    _marker: std::marker::PhantomData<(A, R)>,
+}
+
+impl<A, R> WasiFn<A, R>
+where
+    A: Serialize,
+    R: DeserializeOwned,
+{
+    pub fn new(instance: &Instance, name: &str) -> Result<Self> {
+        let raw = instance
+            .exports
+            .get_function(name)?
+            .native()
+            .map_err(|e| anyhow!("export `{}` is not callable as (u64) -> u64: {}", name, e))?;
+        Ok(WasiFn {
+            raw,
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    /// Serializes `args`, has `alloc` reserve a guest buffer sized for it,
+    /// calls the wrapped export, and deserializes whatever `read_buffer`
+    /// hands back. The input buffer is freed once this call returns, on
+    /// both the success and error paths.
+    pub fn call(
+        &self,
+        args: &A,
+        alloc: impl FnOnce(&[u8]) -> Result<GuestBuffer>,
+        read_buffer: impl FnOnce(WasiBuffer) -> Result<Vec<u8>>,
+    ) -> Result<R> {
+        let serialized = bincode::serialize(args)?;
+        let in_buffer = alloc(&serialized)?;
+        let packed_out = self.raw.call(in_buffer.buf().to_u64())?;
+        let out_buffer = WasiBuffer::from_u64(packed_out);
+        let out_bytes = read_buffer(out_buffer)?;
+        Ok(bincode::deserialize(&out_bytes)?)
+    }
+
+    /// Like `call`, but if the export reports `PENDING_SENTINEL` instead of
+    /// a result buffer, drives `reactor` until the guest's async runtime
+    /// has one ready before reading it back. Used for calls into guest JS
+    /// that may `await` real network I/O or a spawned WASIX thread.
+    pub fn call_with_reactor(
+        &self,
+        args: &A,
+        alloc: impl FnOnce(&[u8]) -> Result<GuestBuffer>,
+        read_buffer: impl FnOnce(WasiBuffer) -> Result<Vec<u8>>,
+        reactor: &crate::reactor::Reactor,
+        async_rt_ptr: i32,
+    ) -> Result<R> {
+        let serialized = bincode::serialize(args)?;
+        let in_buffer = alloc(&serialized)?;
+        let packed_out = self.raw.call(in_buffer.buf().to_u64())?;
+        let packed_out = resolve_pending(packed_out, || {
+            reactor.run_until_ready(async_rt_ptr)?;
+            reactor.take_result(async_rt_ptr)
+        })?;
+        let out_buffer = WasiBuffer::from_u64(packed_out);
+        let out_bytes = read_buffer(out_buffer)?;
+        Ok(bincode::deserialize(&out_bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_and_unpacks_ptr_len() {
+        let buf = WasiBuffer::new(0x1234_5678, 0x9abc_def0);
+        let packed = buf.to_u64();
+        assert_eq!(WasiBuffer::from_u64(packed), buf);
+    }
+
+    #[test]
+    fn zero_length_buffer_round_trips() {
+        let buf = WasiBuffer::new(42, 0);
+        assert_eq!(WasiBuffer::from_u64(buf.to_u64()), buf);
+    }
+
+    #[test]
+    fn pending_sentinel_is_classified_as_pending() {
+        assert_eq!(classify_raw_result(PENDING_SENTINEL), RawCallOutcome::Pending);
+    }
+
+    #[test]
+    fn any_other_packed_value_is_classified_as_ready() {
+        let buf = WasiBuffer::new(4, 16);
+        assert_eq!(classify_raw_result(buf.to_u64()), RawCallOutcome::Ready(buf.to_u64()));
+        assert_eq!(classify_raw_result(0), RawCallOutcome::Ready(0));
+    }
+
+    #[test]
+    fn resolve_pending_returns_immediately_when_already_ready() {
+        let ready = WasiBuffer::new(4, 8).to_u64();
+        let result = resolve_pending(ready, || panic!("must not poll again once already ready"));
+        assert_eq!(result.unwrap(), ready);
+    }
+
+    #[test]
+    fn resolve_pending_keeps_polling_through_multiple_wakeups_that_are_still_pending() {
+        let real_result = WasiBuffer::new(1, 2).to_u64();
+        let mut remaining = vec![PENDING_SENTINEL, PENDING_SENTINEL, real_result];
+        remaining.reverse();
+
+        let result = resolve_pending(PENDING_SENTINEL, || Ok(remaining.pop().unwrap()));
+
+        assert_eq!(result.unwrap(), real_result);
+    }
+
+    #[test]
+    fn resolve_pending_propagates_an_error_from_next() {
+        let result = resolve_pending(PENDING_SENTINEL, || Err(anyhow!("reactor poll failed")));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+/// Errors raised directly by `WasmerHost`, as distinct from opaque guest
+/// failures that get wrapped in `anyhow::Error`.
+#[derive(Debug)]
+pub enum HostError {
+    /// The metering budget installed at construction ran out before the
+    /// call finished; refill points and retry if the caller wants to
+    /// continue.
+    OutOfPoints,
+    /// A guest export handed back a `ptr`/`len` pair that doesn't fit
+    /// inside the instance's current linear memory. Surfaced instead of
+    /// letting the host panic on an out-of-bounds slice, since the guest
+    /// may be untrusted.
+    InvalidGuestBuffer { ptr: u32, len: u32 },
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::OutOfPoints => write!(f, "guest execution exhausted its metering budget"),
+            HostError::InvalidGuestBuffer { ptr, len } => write!(
+                f,
+                "guest buffer at ptr={:#x} len={} is out of bounds of linear memory",
+                ptr, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HostError {}
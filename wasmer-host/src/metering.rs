@@ -0,0 +1,38 @@
+use std::sync::Arc;
+use wasmer::wasmparser::Operator;
+use wasmer_middlewares::Metering;
+
+/// Configuration for the fuel/gas metering installed on a `WasmerHost`'s
+/// engine, bounding how much guest wasm can execute before it traps with
+/// `MeteringPoints::Exhausted`.
+#[derive(Debug, Clone, Copy)]
+pub struct MeteringConfig {
+    pub initial_points: u64,
+}
+
+impl MeteringConfig {
+    pub fn new(initial_points: u64) -> Self {
+        MeteringConfig { initial_points }
+    }
+}
+
+impl Default for MeteringConfig {
+    fn default() -> Self {
+        // Generous enough for a single eval/compile call under normal use;
+        // callers running untrusted guest code should pick a tighter budget.
+        MeteringConfig { initial_points: 10_000_000 }
+    }
+}
+
+/// Charges a flat one point per wasm operator. Coarse, but enough to bound
+/// a runaway loop without having to model per-instruction cost precisely.
+fn cost_function(_operator: &Operator) -> u64 {
+    1
+}
+
+/// Builds the middleware to push onto a compiler config before constructing
+/// the engine, so metering is counted for every instance that compiler
+/// config produces.
+pub fn metering_middleware(config: MeteringConfig) -> Arc<Metering<fn(&Operator) -> u64>> {
+    Arc::new(Metering::new(config.initial_points, cost_function))
+}
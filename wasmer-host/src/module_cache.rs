@@ -0,0 +1,202 @@
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use runtime_registry::registry::RuntimeRegistry;
+use wasmer::{Module, Store};
+
+/// Bumped whenever the on-disk artifact format, or how we key it, changes
+/// in a way that invalidates previously cached modules.
+///
+/// v2 adds the checksum frame written/checked by `write_frame`/`read_frame`
+/// below, so any artifact written by v1 is (correctly) treated as a miss.
+const ARTIFACT_FORMAT_VERSION: u32 = 2;
+
+/// Identifies the compiler/engine pair modules are serialized against.
+/// Artifacts are only ever deserialized back into a `Store` built from the
+/// same pair, so this is part of the cache key alongside the runtime name.
+const ENGINE_KEY: &str = "universal-cranelift";
+
+/// Directory artifacts are cached under when `WASMER_HOST_MODULE_CACHE_DIR`
+/// isn't set. Scoped to this service under the user's own cache directory
+/// rather than the shared, often world-writable system temp dir, since
+/// anything else with write access to that temp dir could otherwise plant
+/// an artifact at a predictable path for us to deserialize.
+fn cache_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("WASMER_HOST_MODULE_CACHE_DIR") {
+        return PathBuf::from(dir);
+    }
+    if let Some(xdg_cache) = std::env::var_os("XDG_CACHE_HOME") {
+        return PathBuf::from(xdg_cache).join("exerum-host").join("modules");
+    }
+    if let Some(home) = std::env::var_os("HOME") {
+        return PathBuf::from(home).join(".cache").join("exerum-host").join("modules");
+    }
+    // No user cache dir to scope to (e.g. a minimal container with no
+    // $HOME): fall back to the shared temp dir rather than failing outright.
+    std::env::temp_dir().join("exerum-host-module-cache")
+}
+
+fn artifact_path(cache_dir: &Path, runtime: &str) -> PathBuf {
+    cache_dir.join(format!(
+        "{}-{}-v{}.artifact",
+        runtime, ENGINE_KEY, ARTIFACT_FORMAT_VERSION
+    ))
+}
+
+/// SipHash of `bytes`, good enough to catch truncation/corruption (a torn
+/// write, a bit-flip) rather than to defend against a deliberately crafted
+/// forgery — the cache dir being scoped to this service's own files is what
+/// keeps a malicious artifact off the read path in the first place.
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Frames `payload` as `[checksum: u64 LE][payload]` for on-disk storage.
+fn write_frame(payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(8 + payload.len());
+    framed.extend_from_slice(&checksum(payload).to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// Inverse of `write_frame`: splits off the checksum and verifies it before
+/// handing back the payload. `None` means the frame is missing, truncated,
+/// or its payload doesn't match its checksum — always treated as a cache
+/// miss by the caller, never as a deserialization attempt.
+fn read_frame(framed: &[u8]) -> Option<&[u8]> {
+    if framed.len() < 8 {
+        return None;
+    }
+    let (checksum_bytes, payload) = framed.split_at(8);
+    let expected = u64::from_le_bytes(checksum_bytes.try_into().unwrap());
+    if checksum(payload) == expected {
+        Some(payload)
+    } else {
+        None
+    }
+}
+
+/// Writes `bytes` to `path` via a temp file in the same directory followed
+/// by a rename, so a crash or a concurrent writer never leaves a reader
+/// looking at a torn, partially-written file: `path` either doesn't exist
+/// yet or atomically becomes the complete new contents.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let dir = path.parent().expect("artifact_path always has a parent");
+    fs::create_dir_all(dir)?;
+    let tmp_path = dir.join(format!(
+        ".{}.tmp-{}-{:?}",
+        path.file_name().and_then(|n| n.to_str()).unwrap_or("artifact"),
+        std::process::id(),
+        std::thread::current().id()
+    ));
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Compiles `runtime` through `registry` against `store`, reusing a
+/// previously serialized `Module` artifact from disk when one exists for
+/// the current runtime name + engine/version key.
+///
+/// Falls back to a full `RuntimeRegistry` compile (and rewrites the cache)
+/// whenever the artifact is missing, checksum-mismatched, stale, or fails
+/// to deserialize, so a cold start, a torn write, or a version bump never
+/// hard-fails — it just pays the Cranelift cost once more. The checksum
+/// check runs before the bytes ever reach `Module::deserialize`, which is
+/// `unsafe` precisely because wasmer only promises safety for bytes it
+/// produced itself and that reached us intact.
+pub fn get_or_compile_cached(registry: &RuntimeRegistry, runtime: &str, store: &Store) -> Result<Module> {
+    let path = artifact_path(&cache_dir(), runtime);
+    if let Ok(framed) = fs::read(&path) {
+        if let Some(payload) = read_frame(&framed) {
+            if let Ok(module) = unsafe { Module::deserialize(store, payload) } {
+                return Ok(module);
+            }
+        }
+        // Missing/mismatched checksum, stale format, wrong engine version,
+        // or corrupt file: fall through to a full compile and let it
+        // overwrite the artifact below.
+    }
+
+    let module = registry.get_module(runtime, store)?;
+    if let Ok(serialized) = module.serialize() {
+        // Best-effort: a failed cache write shouldn't fail the call that
+        // asked for a compiled module.
+        let _ = write_atomic(&path, &write_frame(&serialized));
+    }
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn artifact_path_is_keyed_by_runtime_and_engine() {
+        let dir = Path::new("/cache");
+        let a = artifact_path(dir, "js-runtime");
+        let b = artifact_path(dir, "other-runtime");
+        assert_ne!(a, b);
+        assert!(a.to_string_lossy().contains("js-runtime"));
+        assert!(a.to_string_lossy().contains(ENGINE_KEY));
+        assert!(a.to_string_lossy().contains(&ARTIFACT_FORMAT_VERSION.to_string()));
+    }
+
+    #[test]
+    fn bumping_the_format_version_changes_the_path() {
+        let dir = Path::new("/cache");
+        let current = artifact_path(dir, "js-runtime");
+        let hypothetical_next = dir.join(format!(
+            "js-runtime-{}-v{}.artifact",
+            ENGINE_KEY,
+            ARTIFACT_FORMAT_VERSION + 1
+        ));
+        assert_ne!(current, hypothetical_next);
+    }
+
+    #[test]
+    fn read_frame_round_trips_a_written_frame() {
+        let payload = b"pretend-this-is-a-serialized-module".to_vec();
+        let framed = write_frame(&payload);
+        assert_eq!(read_frame(&framed), Some(payload.as_slice()));
+    }
+
+    #[test]
+    fn read_frame_rejects_a_corrupted_payload() {
+        let payload = b"pretend-this-is-a-serialized-module".to_vec();
+        let mut framed = write_frame(&payload);
+        let last = framed.len() - 1;
+        framed[last] ^= 0xff;
+        assert_eq!(read_frame(&framed), None);
+    }
+
+    #[test]
+    fn read_frame_rejects_a_truncated_file() {
+        assert_eq!(read_frame(b"short"), None);
+        assert_eq!(read_frame(b""), None);
+    }
+
+    #[test]
+    fn write_atomic_never_leaves_a_temp_file_behind_on_success() {
+        let dir = std::env::temp_dir().join(format!(
+            "exerum-host-module-cache-test-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let path = dir.join("runtime-v2.artifact");
+        write_atomic(&path, b"contents").unwrap();
+
+        let entries: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![path.file_name().unwrap().to_owned()]);
+        assert_eq!(fs::read(&path).unwrap(), b"contents");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+
+use crate::metering::MeteringConfig;
+use crate::WasmerHost;
+
+/// A pool of pre-built `T`s checked out and returned by a multithreaded
+/// caller instead of serializing on a single shared resource.
+///
+/// The free-list/checkout/release mechanics here are independent of what
+/// `T` actually is; `InstancePool::new` below is the `WasmerHost`-specific
+/// constructor that compiles one shared `Module` and gives each pooled
+/// host its own `Instance` (and therefore its own linear memory and
+/// metering budget) instantiated from it, all sharing the module's `Store`.
+pub struct InstancePool<T> {
+    factory: Box<dyn Fn() -> Result<T> + Send + Sync>,
+    free: Mutex<Vec<T>>,
+}
+
+impl<T> InstancePool<T> {
+    /// Pre-builds `size` items via `factory` and keeps `factory` around to
+    /// build more on demand if the pool ever runs dry.
+    pub fn with_factory(
+        size: usize,
+        factory: impl Fn() -> Result<T> + Send + Sync + 'static,
+    ) -> Result<Arc<Self>> {
+        let mut free = Vec::with_capacity(size);
+        for _ in 0..size {
+            free.push(factory()?);
+        }
+        Ok(Arc::new(InstancePool {
+            factory: Box::new(factory),
+            free: Mutex::new(free),
+        }))
+    }
+
+    /// Checks out an item, building a fresh one via the factory if the pool
+    /// is currently empty rather than blocking. Returns a `PooledHost` that
+    /// goes back to the pool when dropped.
+    pub fn checkout(self: &Arc<Self>) -> Result<PooledHost<T>> {
+        let leased = self
+            .free
+            .lock()
+            .map_err(|_| anyhow!("instance pool lock poisoned"))?
+            .pop();
+        let item = match leased {
+            Some(item) => item,
+            None => (self.factory)()?,
+        };
+        Ok(PooledHost {
+            pool: Arc::clone(self),
+            item: Some(item),
+        })
+    }
+
+    fn release(&self, item: T) {
+        if let Ok(mut free) = self.free.lock() {
+            free.push(item);
+        }
+    }
+}
+
+impl InstancePool<WasmerHost> {
+    /// Compiles `runtime` once and pre-instantiates `size` independent
+    /// `WasmerHost`s around the shared `Module`.
+    ///
+    /// Every `WasmerHost` this produces shares one `Store` (see
+    /// `WasmerHost::from_module`'s doc comment for why that's safe to run
+    /// concurrently) while each gets its own `Instance`, memory, globals and
+    /// metering budget, so leasing two out of the pool to two threads and
+    /// calling `run_module_function` on each concurrently is sound.
+    pub fn new(runtime: &str, size: usize, metering: MeteringConfig) -> Result<Arc<Self>> {
+        let module = WasmerHost::compile_module(runtime, metering);
+        Self::with_factory(size, move || WasmerHost::from_module(&module, metering))
+    }
+}
+
+/// A `T` leased out of an `InstancePool`. Derefs to the item; returns it to
+/// the pool automatically on drop.
+pub struct PooledHost<T> {
+    pool: Arc<InstancePool<T>>,
+    item: Option<T>,
+}
+
+impl<T> Deref for PooledHost<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("item taken before drop")
+    }
+}
+
+impl<T> DerefMut for PooledHost<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().expect("item taken before drop")
+    }
+}
+
+impl<T> Drop for PooledHost<T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.pool.release(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counting_pool(size: usize) -> (Arc<InstancePool<usize>>, Arc<AtomicUsize>) {
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&created);
+        let pool = InstancePool::with_factory(size, move || Ok(counter.fetch_add(1, Ordering::SeqCst))).unwrap();
+        (pool, created)
+    }
+
+    #[test]
+    fn checkout_reuses_a_released_item_instead_of_rebuilding() {
+        let (pool, created) = counting_pool(1);
+
+        let first_id = *pool.checkout().unwrap();
+        let second_id = *pool.checkout().unwrap();
+
+        assert_eq!(first_id, second_id, "the released item should come back, not a freshly built one");
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn checkout_builds_a_new_item_when_the_pool_is_empty() {
+        let (pool, created) = counting_pool(0);
+
+        let _leased = pool.checkout().unwrap();
+
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn two_concurrently_leased_items_are_distinct() {
+        let (pool, created) = counting_pool(1);
+
+        let first = pool.checkout().unwrap();
+        let second = pool.checkout().unwrap();
+
+        assert_ne!(*first, *second, "a second checkout while the first is still leased must not alias it");
+        assert_eq!(created.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use wasmer::{Instance, TypedFunc};
+
+/// Backoff bounds for `Reactor::run_until_ready`. Starting this small keeps
+/// latency low for I/O that resolves almost immediately; capping it at a
+/// few milliseconds keeps a pool of hosts waiting on real network latency
+/// from pinning a core each for the full duration of that I/O.
+const INITIAL_POLL_BACKOFF: Duration = Duration::from_micros(50);
+const MAX_POLL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Drives the guest's async runtime after it suspends on real I/O or a
+/// spawned WASIX thread instead of blocking the host thread on it.
+///
+/// Guest JS can now `await` network calls and spawn concurrent tasks via
+/// WASIX. When a top-level call like `run_module_function` reports
+/// `PENDING_SENTINEL` instead of a result, `Reactor` re-enters the guest's
+/// exported `__wasix_reactor_poll` until the async runtime says progress has
+/// been made, then `__wasix_reactor_result` to fetch the packed result.
+pub struct Reactor {
+    poll_fn: TypedFunc<i32, i32>,
+    result_fn: TypedFunc<i32, u64>,
+}
+
+impl Reactor {
+    pub fn new(instance: &Instance) -> Result<Self> {
+        let poll_fn = instance.exports.get_function("__wasix_reactor_poll")?.native()?;
+        let result_fn = instance.exports.get_function("__wasix_reactor_result")?.native()?;
+        Ok(Reactor { poll_fn, result_fn })
+    }
+
+    /// Polls the guest's async runtime once. Returns `true` once a spawned
+    /// thread or pending I/O has made progress and the runtime has
+    /// something runnable, `false` if the host should poll again.
+    fn poll_once(&self, async_rt_ptr: i32) -> Result<bool> {
+        Ok(self.poll_fn.call(async_rt_ptr)? != 0)
+    }
+
+    /// Repeatedly polls the reactor until the guest reports it has made
+    /// progress on the pending call, sleeping with exponential backoff
+    /// between polls instead of busy-spinning the host thread while the
+    /// guest waits on network I/O or a spawned thread.
+    pub fn run_until_ready(&self, async_rt_ptr: i32) -> Result<()> {
+        let mut backoff = INITIAL_POLL_BACKOFF;
+        while !self.poll_once(async_rt_ptr)? {
+            std::thread::sleep(backoff);
+            backoff = next_poll_backoff(backoff);
+        }
+        Ok(())
+    }
+
+    /// Fetches the packed result buffer for the call that was pending.
+    pub fn take_result(&self, async_rt_ptr: i32) -> Result<u64> {
+        Ok(self.result_fn.call(async_rt_ptr)?)
+    }
+}
+
+/// Doubles `current`, capped at `MAX_POLL_BACKOFF`.
+fn next_poll_backoff(current: Duration) -> Duration {
+    (current * 2).min(MAX_POLL_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = INITIAL_POLL_BACKOFF;
+        for _ in 0..3 {
+            let next = next_poll_backoff(backoff);
+            assert_eq!(next, backoff * 2);
+            backoff = next;
+        }
+    }
+
+    #[test]
+    fn backoff_never_exceeds_the_cap() {
+        let mut backoff = MAX_POLL_BACKOFF;
+        for _ in 0..5 {
+            backoff = next_poll_backoff(backoff);
+            assert_eq!(backoff, MAX_POLL_BACKOFF);
+        }
+    }
+}
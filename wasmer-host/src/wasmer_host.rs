@@ -1,13 +1,44 @@
 use wasmer::ImportObject;
-use wasmer::{Module, Store, Instance, Val};
+use wasmer::{Module, Store, Instance, TypedFunc};
 use wasmer_wasi::WasiState;
-use anyhow::{Result};
+use anyhow::Result;
 use protocol::{WasmHost, RunModuleFunctionParameters};
 use runtime_registry::registry::RuntimeRegistry;
-use wasmer::Value;
 use wasmer_compiler_cranelift::Cranelift;
 use wasmer_engine_universal::Universal;
-use wasmer_wasi_experimental_network::runtime_impl::get_namespace;
+use wasmer_wasix::net::get_namespace as get_wasix_net_namespace;
+use wasmer_wasix::thread::get_namespace as get_wasix_thread_namespace;
+use serde::Serialize;
+use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, MeteringPoints};
+
+mod buffer;
+mod error;
+mod metering;
+mod module_cache;
+mod pool;
+mod reactor;
+use buffer::{GuestBuffer, WasiBuffer, WasiFn};
+use error::HostError;
+use metering::{metering_middleware, MeteringConfig};
+use reactor::Reactor;
+
+pub use pool::{InstancePool, PooledHost};
+
+/// Arguments for the guest `compile_module` export.
+#[derive(Serialize)]
+struct CompileModuleParams {
+    async_rt_ptr: u32,
+    js_rt_ptr: u32,
+    source: Vec<u8>,
+}
+
+/// Arguments for the guest `run` (eval) export.
+#[derive(Serialize)]
+struct EvalParams {
+    async_rt_ptr: u32,
+    js_rt_ptr: u32,
+    source: Vec<u8>,
+}
 
 pub struct WasmerHost {
     instance: Instance,
@@ -15,8 +46,21 @@ pub struct WasmerHost {
     js_rt_ptr: i32,
     /// Pointer to async runtime
     async_rt_ptr: i32,
-    /// The internal wasm buffer offset
-    parameter_buffer_ptr: i32,
+    /// Guest export that reserves a buffer of the requested length and
+    /// returns its pointer.
+    alloc_fn: TypedFunc<u32, u32>,
+    /// Guest export that releases a buffer previously returned by
+    /// `alloc_fn`, given its packed pointer/length.
+    free_fn: TypedFunc<u64, ()>,
+    /// Typed wrapper over the guest `compile_module` export.
+    compile_module_fn: WasiFn<CompileModuleParams, Vec<u8>>,
+    /// Typed wrapper over the guest `run` export.
+    run_fn: WasiFn<EvalParams, ()>,
+    /// Typed wrapper over the guest `run_module_function` export.
+    run_module_function_fn: WasiFn<RunModuleFunctionParameters, String>,
+    /// Resumes the guest's async runtime when a call suspends on network
+    /// I/O or a spawned WASIX thread instead of returning immediately.
+    reactor: Reactor,
 }
 
 impl WasmerHost {
@@ -28,117 +72,245 @@ impl WasmerHost {
         &mut self.instance
     }
 
-    pub fn new_wasi_dev(runtime: &str) -> Self {
+    /// Instantiates a wasi dev host for `runtime`, bounding how much guest
+    /// wasm may execute before `eval`/`run_module_function` trap with
+    /// `HostError::OutOfPoints`.
+    pub fn new_wasi_dev(runtime: &str, metering: MeteringConfig) -> Self {
+        let module = WasmerHost::compile_module(runtime, metering);
+        WasmerHost::from_module(&module, metering).unwrap()
+    }
+
+    /// Compiles `runtime` into a `Module` with the metering middleware that
+    /// `metering` describes baked in. The result can be instantiated more
+    /// than once via `from_module`, so callers that need many independent
+    /// hosts (see `InstancePool`) only pay Cranelift once.
+    ///
+    /// Goes through `module_cache::get_or_compile_cached`, which loads a
+    /// previously serialized artifact for `runtime` when one exists instead
+    /// of recompiling with Cranelift on every cold start.
+    pub(crate) fn compile_module(runtime: &str, metering: MeteringConfig) -> Module {
+        let registry = RuntimeRegistry::new();
+        let mut compiler_config = Cranelift::default();
+        compiler_config.push_middleware(metering_middleware(metering));
+        let store = Store::new(&Universal::new(compiler_config).engine());
+        module_cache::get_or_compile_cached(&registry, runtime, &store).unwrap()
+    }
+
+    /// Instantiates `module` into a fresh, independent `WasmerHost`: its own
+    /// wasi imports, `Instance`, js/async runtime pointers and metering
+    /// budget, all distinct from any other host built from the same shared
+    /// `Module`.
+    ///
+    /// Every `WasmerHost` built from the same `Module` (e.g. the members of
+    /// an `InstancePool`) shares one `Store`, rather than each getting its
+    /// own. That's intentional, not an oversight: a `Store` only holds the
+    /// engine and the signature/type registry, both read-only once the
+    /// module is compiled and internally synchronized for concurrent
+    /// lookups; none of that is mutated per call. All of the state a call
+    /// actually mutates — linear memory, globals, the table, the metering
+    /// counter — lives on the `Instance`, and `Instance::new` below gives
+    /// each `WasmerHost` its own. So two pooled hosts running
+    /// `run_module_function` concurrently on separate threads touch
+    /// disjoint `Instance` state and never race, even though they're
+    /// instantiated from one shared `Store`.
+    ///
+    /// This repo has no compiled `.wasm` fixture or build environment to
+    /// actually instantiate two real `Instance`s and drive them
+    /// concurrently in a test, so that invariant is documented here rather
+    /// than exercised — add a concurrent-`run_module_function` test across
+    /// two pooled hosts once real wasm/build infra is available.
+    pub(crate) fn from_module(module: &Module, metering: MeteringConfig) -> Result<Self> {
         println!("Creating wasi dev instance.");
-        let instance = WasmerHost::new_wasi_dev_instance(runtime);
+        // Reuse the store the module was compiled/bound to rather than an
+        // unrelated fresh one, so the host imports built here are tied to
+        // the same store as the module they're instantiated against.
+        let store = module.store();
+        let import_object = init_wasi_dev_imports(store, module);
+        let instance = Instance::new(module, &import_object)?;
         println!("Wasi dev instance created.");
+        set_remaining_points(&instance, metering.initial_points);
         // Init js runtime object for reuse
-        let new_js_rt_ptr = instance.exports.get_function("new_runtime").unwrap();
-        let js_rt_ptr = new_js_rt_ptr.call(&[]).unwrap()[0].i32().unwrap();
+        let new_js_rt_ptr = instance.exports.get_function("new_runtime")?;
+        let js_rt_ptr = new_js_rt_ptr.call(&[])?[0].i32().unwrap();
         // Init async runtime for reuse
-        let new_async_rt_ptr = instance.exports.get_function("new_async_runtime").unwrap();
-        let async_rt_ptr = new_async_rt_ptr.call(&[]).unwrap()[0].i32().unwrap();
-        // Get the buffer pointer
-        let buffer_fn = instance.exports.get_function("parameter_buffer_ptr").unwrap();
-        let parameter_buffer_ptr = buffer_fn.call(&[]).unwrap()[0].i32().unwrap();
-        WasmerHost {
+        let new_async_rt_ptr = instance.exports.get_function("new_async_runtime")?;
+        let async_rt_ptr = new_async_rt_ptr.call(&[])?[0].i32().unwrap();
+        // Guest-side allocator used to size a buffer per call instead of
+        // reusing one fixed region, so large payloads and reentrant calls
+        // are both safe.
+        let alloc_fn = instance.exports.get_function("__alloc_buffer")?.native()?;
+        let free_fn = instance.exports.get_function("__free_buffer")?.native()?;
+        // Typed wrappers over the exports that move host<->guest buffers.
+        let compile_module_fn = WasiFn::new(&instance, "compile_module")?;
+        let run_fn = WasiFn::new(&instance, "run")?;
+        let run_module_function_fn = WasiFn::new(&instance, "run_module_function")?;
+        let reactor = Reactor::new(&instance)?;
+        Ok(WasmerHost {
             instance,
             js_rt_ptr,
             async_rt_ptr,
-            parameter_buffer_ptr,
-        }
+            alloc_fn,
+            free_fn,
+            compile_module_fn,
+            run_fn,
+            run_module_function_fn,
+            reactor,
+        })
     }
 
-    /// Instantiates a wasmer instance and initializes it with wasi host functions
-    /// and experimental network host functions for development environment.
-    fn new_wasi_dev_instance(runtime: &str) -> Instance {
-        let registry = RuntimeRegistry::new();
-        let store = Store::new(&Universal::new(Cranelift::default()).engine());
-        // Check cache for module
-        let module = registry.get_module(runtime, &store).unwrap();
-        let import_object = init_wasi_dev_imports(&store, &module);
-        let instance = Instance::new(&module, &import_object).unwrap();
-        instance
+    /// Fetches a fresh view of guest linear memory and hands it to `f`.
+    ///
+    /// Guest memory can grow (and thus move) during any call into the guest,
+    /// so a view obtained before one call is not safe to reuse after
+    /// another. Every read or write goes through this helper instead of a
+    /// long-lived borrow, so it always sees the current base pointer.
+    #[inline]
+    fn with_memory<T>(&self, f: impl FnOnce(&mut [u8]) -> Result<T>) -> Result<T> {
+        let memory = self.instance.exports.get_memory("memory")?;
+        let data = unsafe { memory.data_unchecked_mut() };
+        f(data)
     }
 
+    /// Asks the guest to reserve a buffer sized for `bytes`, copies them in,
+    /// and returns an RAII guard that frees the buffer on drop.
+    ///
+    /// `ptr` comes straight from the guest's `__alloc_buffer` export, which
+    /// may be buggy or hostile, so the write range is checked against the
+    /// current memory size rather than trusted outright.
     #[inline]
-    fn read_returned_value(&self, buffer: &mut [u8], len: i32) -> Vec<u8> {
-        buffer[self.parameter_buffer_ptr as usize..(self.parameter_buffer_ptr as usize + len as usize)]
-            .to_vec()
+    fn alloc_guest_buffer(&self, bytes: &[u8]) -> Result<GuestBuffer> {
+        let ptr = self.alloc_fn.call(bytes.len() as u32)?;
+        let len = bytes.len() as u32;
+        // Take the guard before the bounds check so an error below still
+        // frees the buffer the guest just reserved.
+        let guard = GuestBuffer::new(WasiBuffer::new(ptr, len), self.free_fn.clone());
+        self.with_memory(|data| {
+            let end = (ptr as usize).checked_add(bytes.len());
+            let dest = end.and_then(|end| data.get_mut(ptr as usize..end));
+            match dest {
+                Some(dest) => {
+                    dest.copy_from_slice(bytes);
+                    Ok(())
+                }
+                None => Err(anyhow::Error::new(HostError::InvalidGuestBuffer { ptr, len })),
+            }
+        })?;
+        Ok(guard)
     }
 
+    /// Reads the bytes described by `buf` back out of guest memory, then
+    /// frees that buffer on the guest's behalf.
+    ///
+    /// `buf` comes straight from the guest (a raw export return value or
+    /// the reactor's pending-result fetch), so the read range is checked
+    /// against the current memory size rather than trusted outright.
     #[inline]
-    fn slice_to_buffer(&self, buffer: &mut [u8], source: &[u8]) {
-        buffer[self.parameter_buffer_ptr as usize..(self.parameter_buffer_ptr as usize + source.len())].copy_from_slice(source);
+    fn read_and_free_guest_buffer(&self, buf: WasiBuffer) -> Result<Vec<u8>> {
+        let bytes = self.with_memory(|data| {
+            let end = (buf.ptr as usize).checked_add(buf.len as usize);
+            let src = end.and_then(|end| data.get(buf.ptr as usize..end));
+            src.map(|src| src.to_vec()).ok_or_else(|| {
+                anyhow::Error::new(HostError::InvalidGuestBuffer {
+                    ptr: buf.ptr,
+                    len: buf.len,
+                })
+            })
+        })?;
+        self.free_fn.call(buf.to_u64())?;
+        Ok(bytes)
+    }
+
+    /// Points left in the metering budget, or `0` if it's already exhausted.
+    pub fn remaining_points(&self) -> u64 {
+        match get_remaining_points(&self.instance) {
+            MeteringPoints::Remaining(points) => points,
+            MeteringPoints::Exhausted => 0,
+        }
+    }
+
+    /// Tops the metering budget back up, e.g. between successive calls made
+    /// on behalf of the same untrusted caller.
+    pub fn refill_points(&mut self, points: u64) {
+        set_remaining_points(&self.instance, points);
+    }
+
+    fn is_out_of_points(&self) -> bool {
+        matches!(get_remaining_points(&self.instance), MeteringPoints::Exhausted)
+    }
+
+    /// If `result` failed because the metering budget ran out, rewrites the
+    /// error into `HostError::OutOfPoints` so callers can match on it
+    /// instead of guessing at the trap message.
+    fn map_metering_error<T>(&self, result: Result<T>) -> Result<T> {
+        rewrite_if_out_of_points(self.is_out_of_points(), result)
+    }
+
+    /// Returns `HostError::OutOfPoints` instead of panicking when the
+    /// metering budget runs out, so a caller can refill points and retry.
+    ///
+    /// This is the entry point to actually call for that behavior: the
+    /// `WasmHost::eval` trait method below can't use it, because
+    /// `protocol::WasmHost::eval` (defined outside this repo) returns `()`
+    /// with no channel back to the caller, so it still has to `unwrap()`.
+    /// Prefer calling `eval_checked` directly wherever the trait object
+    /// isn't required.
+    pub fn eval_checked(&self, js: &str) -> Result<()> {
+        let params = EvalParams {
+            async_rt_ptr: self.async_rt_ptr as u32,
+            js_rt_ptr: self.js_rt_ptr as u32,
+            source: js.as_bytes().to_vec(),
+        };
+        let result = self.run_fn.call(
+            &params,
+            |bytes| self.alloc_guest_buffer(bytes),
+            |buf| self.read_and_free_guest_buffer(buf),
+        );
+        self.map_metering_error(result)
     }
 }
 
 impl WasmHost for WasmerHost {
 
     fn compile_to_bytecode(&mut self, _: &str, code: &str) -> Result<std::vec::Vec<u8>> {
-        let source = code.as_bytes();
-        let memory = self.instance.exports.get_memory("memory")?;
-        let data = unsafe { memory.data_unchecked_mut() };
-        // Copy source code to the buffer
-        self.slice_to_buffer(data, source);
-        // Get function pointer
-        let compile_module_fn = self.instance.exports.get_function("compile_module")?;
-        // Cal the function
         println!("Calling compile_module_fn...");
-        let bytecode_size = compile_module_fn.call(&[
-            Value::I32(self.async_rt_ptr as i32),
-            Value::I32(self.js_rt_ptr as i32),
-            Value::I32(source.len() as i32),
-        ])?[0]
-            .i32()
-            .unwrap();
-        // Copy returned data
-        let mut bytecode = Vec::with_capacity(bytecode_size as usize);
-        bytecode.resize(bytecode_size as usize, 0);
-        bytecode.copy_from_slice(
-            &data[self.parameter_buffer_ptr as usize..(self.parameter_buffer_ptr as usize + bytecode_size as usize)],
-        );
+        let params = CompileModuleParams {
+            async_rt_ptr: self.async_rt_ptr as u32,
+            js_rt_ptr: self.js_rt_ptr as u32,
+            source: code.as_bytes().to_vec(),
+        };
+        let bytecode = self.map_metering_error(self.compile_module_fn.call(
+            &params,
+            |bytes| self.alloc_guest_buffer(bytes),
+            |buf| self.read_and_free_guest_buffer(buf),
+        ))?;
         println!("Done compiling to bytecode.");
         Ok(bytecode)
     }
 
     fn eval(&self, js: &str) {
-        let js_bytes = js.as_bytes();
-        let memory = self.instance.exports.get_memory("memory").unwrap();
-        let js_rt_eval_fn = self
-            .instance
-            .exports
-            .get_function("run")
-            .unwrap();
-        let data = unsafe { memory.data_unchecked_mut() };
-        self.slice_to_buffer(data, js_bytes);
-        js_rt_eval_fn.call(&[
-            Val::I32(self.async_rt_ptr)
-            Val::I32(self.js_rt_ptr),
-            Val::I32(js_bytes.len() as i32)]).unwrap()[0]
-            .i32()
-            .unwrap();
+        // NOTE: this still aborts the process on metering exhaustion.
+        // `protocol::WasmHost::eval` returns `()`, not `Result`, so there is
+        // no way to report `HostError::OutOfPoints` through this trait
+        // method without changing that signature, which lives in the
+        // external `protocol` crate, outside this repo. Callers that need
+        // exhaustion reported as an error rather than a panic must call
+        // `eval_checked` directly instead of going through `WasmHost::eval`.
+        self.eval_checked(js).unwrap()
     }
 
     fn run_module_function(&self, parameters: &mut RunModuleFunctionParameters) -> Result<String> {
-        let memory = self.instance.exports.get_memory("memory").unwrap();
-        let run_module_function = self
-            .instance
-            .exports
-            .get_function("run_module_function")
-            .unwrap();
-        // Get the wasm memory as mutable slice.
-        let data = unsafe { memory.data_unchecked_mut() };
         parameters.set_rt(self.js_rt_ptr as u32);
-        let serialized = bincode::serialize(&parameters).unwrap();
-        self.slice_to_buffer(data, &serialized);
-        let res = run_module_function.call(&[
-            Val::I32(self.async_rt_ptr)
-            Val::I32(serialized.len() as i32)])?[0]
-            .i32()
-            .unwrap();
-        let json_bytes = self.read_returned_value(data, res);
-        Ok(String::from_utf8(json_bytes).unwrap())
+        // Guest JS may `await` a real network call or a spawned WASIX
+        // thread here, so drive the reactor until a result is ready instead
+        // of assuming the export always returns synchronously.
+        let result = self.run_module_function_fn.call_with_reactor(
+            parameters,
+            |bytes| self.alloc_guest_buffer(bytes),
+            |buf| self.read_and_free_guest_buffer(buf),
+            &self.reactor,
+            self.async_rt_ptr,
+        );
+        self.map_metering_error(result)
     }
 }
 
@@ -146,12 +318,56 @@ pub fn init_wasi_dev_imports(store: &Store, module: &Module) -> ImportObject {
     let mut wasi_env = WasiState::new("state")
         .finalize().unwrap();
     let mut import_object = wasi_env.import_object(&module).unwrap();
-    let (n, exports) = get_namespace(store, &wasi_env);
-    import_object.register(n, exports);
+    // WASIX networking (full sockets, not just the experimental namespace)
+    // plus thread-spawn/thread-local-storage, so guest JS can open real
+    // connections and run concurrent tasks instead of blocking on I/O.
+    let (net_ns, net_exports) = get_wasix_net_namespace(store, &wasi_env);
+    import_object.register(net_ns, net_exports);
+    let (thread_ns, thread_exports) = get_wasix_thread_namespace(store, &wasi_env);
+    import_object.register(thread_ns, thread_exports);
     import_object
 }
 
 pub fn compile_to_bytecode(runtime: &str, js: &str) -> Result<Vec<u8>> {
-    let mut rt = WasmerHost::new_wasi_dev(runtime);
+    let mut rt = WasmerHost::new_wasi_dev(runtime, MeteringConfig::default());
     rt.compile_to_bytecode("mod1", js)
 }
+
+/// If `out_of_points` is set, rewrites any error in `result` into
+/// `HostError::OutOfPoints`; otherwise passes it through unchanged. Split
+/// out of `WasmerHost::map_metering_error` so the rewriting logic is
+/// testable without a real `Instance`.
+fn rewrite_if_out_of_points<T>(out_of_points: bool, result: Result<T>) -> Result<T> {
+    result.map_err(|err| {
+        if out_of_points {
+            anyhow::Error::new(HostError::OutOfPoints)
+        } else {
+            err
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_points_rewrites_any_error() {
+        let original: Result<()> = Err(anyhow::anyhow!("guest trapped"));
+        let rewritten = rewrite_if_out_of_points(true, original);
+        assert!(rewritten.unwrap_err().downcast_ref::<HostError>().is_some());
+    }
+
+    #[test]
+    fn not_out_of_points_passes_the_original_error_through() {
+        let original: Result<()> = Err(anyhow::anyhow!("some other guest trap"));
+        let rewritten = rewrite_if_out_of_points(false, original);
+        assert!(rewritten.unwrap_err().downcast_ref::<HostError>().is_none());
+    }
+
+    #[test]
+    fn success_is_unaffected_either_way() {
+        assert_eq!(rewrite_if_out_of_points(true, Ok(42)).unwrap(), 42);
+        assert_eq!(rewrite_if_out_of_points(false, Ok(42)).unwrap(), 42);
+    }
+}